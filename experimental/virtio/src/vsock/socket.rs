@@ -14,125 +14,383 @@
 // limitations under the License.
 //
 
-use alloc::collections::VecDeque;
+use alloc::{collections::BTreeMap, collections::VecDeque, rc::Rc, vec::Vec};
+use core::{cell::RefCell, num::Wrapping};
 
 use super::{
     packet::{Packet, VSockFlags, VSockOp, HEADER_SIZE},
     VSock, DATA_BUFFER_SIZE, QUEUE_SIZE,
 };
-use core::num::Wrapping;
 
-/// The maximum buffer size used by the socket.
+/// The size of our RX buffer that we advertise to the peer as `buf_alloc`.
 ///
-/// This is used for flow-control calculations. For now we use the maximum size seeing that we don't
-/// have a fixed limit and don't want to send too many credit update packets.
-const STREAM_BUFFER_LENGTH: Wrapping<u32> = Wrapping(u32::MAX);
+/// This has to match the actual bounded capacity we can buffer on the receive side
+/// (`DATA_BUFFER_SIZE * QUEUE_SIZE`): advertising anything larger (e.g. `u32::MAX`) would make the
+/// peer's view of our free space meaningless, since it is computed relative to this value.
+const RX_BUF_SIZE: Wrapping<u32> = Wrapping((DATA_BUFFER_SIZE * QUEUE_SIZE) as u32);
 
-/// The limit that triggers a voluntary credit update message to avoid stalling.
+/// The low-water mark that triggers a voluntary credit update message to avoid stalling.
 ///
-/// If the peer's calculation of our free buffer space falls below this point (e.g when we receive a
-/// lot of data without sending any packets back) we send a credit update packet to make sure the
-/// peer knows we have more space available.
-const CREDIT_UPDATE_LIMIT: Wrapping<u32> = Wrapping((DATA_BUFFER_SIZE * QUEUE_SIZE) as u32);
+/// If the peer's calculation of our free buffer space (derived from the `fwd_cnt` we last
+/// advertised) falls below this point -- e.g. when we receive a lot of data without sending any
+/// packets back -- we send a credit update packet to make sure the peer knows we have more space
+/// available before it runs out of credit to send us anything at all.
+const CREDIT_UPDATE_THRESHOLD: Wrapping<u32> = Wrapping(RX_BUF_SIZE.0 / 4);
 
 /// The maximum size of the payload of a single packet to ensure it fits into a single buffer in the
 /// queue.
 const MAX_PAYLOAD_SIZE: usize = DATA_BUFFER_SIZE - HEADER_SIZE;
 
+/// The capacity of the outbound TX buffer that `write_all` appends to and `flush` drains.
+///
+/// Bounding it means a caller that writes faster than the peer can accept data is held back by
+/// `write_all` calling `flush` rather than letting the buffer grow without limit.
+const TX_BUFFER_CAPACITY: usize = DATA_BUFFER_SIZE * QUEUE_SIZE;
+
+/// The pair of ports that identifies a connection to the multiplexer: our local port together with
+/// the port of the peer we are talking to.
+type ConnectionKey = (u32, u32);
+
+/// Shared handle to a [`SocketMultiplexer`], held by every [`Socket`], [`SocketConnector`] and
+/// [`SocketListener`] that was created from it.
+pub type MultiplexerHandle = Rc<RefCell<SocketMultiplexer>>;
+
+/// Demultiplexes a single `VSock` transport across many simultaneous connections.
+///
+/// The virtio-vsock device only gives us one pair of virtqueues for the whole transport, so without
+/// something in front of it only one connection could ever be live: whichever one happened to be
+/// polling would silently consume (and drop) packets addressed to every other connection. The
+/// multiplexer owns the `VSock` instead, reads every inbound `Packet` and routes it to the queue of
+/// the connection it is addressed to (identified by the `(local_port, host_port)` pair), and
+/// serializes all outbound writes. This mirrors the multi-connection manager approach used by
+/// virtio-drivers' socket module.
+pub struct SocketMultiplexer {
+    /// The vsock device driver. This is the only place that is allowed to touch it directly; every
+    /// connection goes through `poll_for`/`poll_listener` and `write_packet` instead.
+    vsock: VSock,
+    /// Packets that have already been read off the driver but not yet consumed by the connection
+    /// (or in-flight `SocketConnector`) they are addressed to, keyed by `(local_port, host_port)`.
+    /// Bounded per connection by `RX_BUF_SIZE` -- see `route_incoming` -- since that is the receive
+    /// window we advertised to the peer as `buf_alloc`.
+    inbound: BTreeMap<ConnectionKey, VecDeque<Packet>>,
+    /// Packets that have already been read off the driver but not yet consumed by a
+    /// `SocketListener` listening on that local port, keyed by `local_port`. A packet only lands
+    /// here if it does not belong to a connection registered in `inbound`; this is where `Request`
+    /// packets end up, since the listener doesn't have a `host_port` to register a `ConnectionKey`
+    /// under until the request arrives.
+    listening: BTreeMap<u32, VecDeque<Packet>>,
+}
+
+impl SocketMultiplexer {
+    /// Creates a new multiplexer that takes ownership of `vsock`.
+    pub fn new(vsock: VSock) -> MultiplexerHandle {
+        Rc::new(RefCell::new(Self {
+            vsock,
+            inbound: BTreeMap::new(),
+            listening: BTreeMap::new(),
+        }))
+    }
+
+    /// Registers a connection (or a `SocketConnector`'s provisional key) so packets addressed to it
+    /// are queued rather than dropped.
+    fn register(&mut self, key: ConnectionKey) {
+        self.inbound.entry(key).or_insert_with(VecDeque::new);
+    }
+
+    /// Stops queuing packets for a connection that is going away.
+    fn unregister(&mut self, key: ConnectionKey) {
+        self.inbound.remove(&key);
+    }
+
+    /// Registers a listener so `Request` packets addressed to `local_port` are queued for it rather
+    /// than dropped.
+    fn register_listener(&mut self, local_port: u32) {
+        self.listening.entry(local_port).or_insert_with(VecDeque::new);
+    }
+
+    /// Stops queuing packets for a listener that is going away.
+    fn unregister_listener(&mut self, local_port: u32) {
+        self.listening.remove(&local_port);
+    }
+
+    /// Writes a packet to the underlying driver.
+    fn write_packet(&mut self, packet: &mut Packet) {
+        self.vsock.write_packet(packet);
+    }
+
+    /// Reads a single packet off the driver, if one is available, and routes it to whichever
+    /// registered connection or listener it is addressed to, queuing it there.
+    ///
+    /// A packet addressed to neither a registered connection nor a registered listener cannot be
+    /// delivered to anyone and is dropped, rather than being handed to whatever caller happens to
+    /// be polling -- that would let an unrelated, already-established connection observe (and
+    /// mistake for a protocol violation) a handshake packet meant for a different connector or
+    /// listener.
+    ///
+    /// Returns `true` if a packet was read (whether or not it could be routed anywhere), `false` if
+    /// none was available.
+    fn route_incoming(&mut self, blocking: bool) -> bool {
+        let packet = match self.vsock.read_filtered_packet(|_| true, blocking) {
+            Some(packet) => packet,
+            None => return false,
+        };
+        let packet_key = (packet.get_dst_port(), packet.get_src_port());
+        if let Some(queue) = self.inbound.get_mut(&packet_key) {
+            // We advertised `RX_BUF_SIZE` as our `buf_alloc`, so a well-behaved peer never has more
+            // than that much of our unconsumed payload in flight. An untrusted host can ignore that
+            // and keep sending anyway, so enforce the cap here rather than buffering without bound:
+            // reset the connection instead of queuing past it.
+            let queued_bytes: usize = queue.iter().map(|queued| queued.get_payload().len()).sum();
+            if queued_bytes + packet.get_payload().len() > RX_BUF_SIZE.0 as usize {
+                // Replace what is queued with a local `Rst`, so the connection sees the reset the
+                // next time it polls instead of waiting on a queue that nothing reaches any more.
+                queue.clear();
+                if let Ok(reset) =
+                    Packet::new_control(packet.get_src_port(), packet.get_dst_port(), VSockOp::Rst)
+                {
+                    queue.push_back(reset);
+                }
+                if let Ok(mut rst) =
+                    Packet::new_control(packet.get_dst_port(), packet.get_src_port(), VSockOp::Rst)
+                {
+                    self.vsock.write_packet(&mut rst);
+                }
+                return true;
+            }
+            queue.push_back(packet);
+            return true;
+        }
+        if let Some(queue) = self.listening.get_mut(&packet.get_dst_port()) {
+            queue.push_back(packet);
+            return true;
+        }
+        true
+    }
+
+    /// Returns the next packet addressed to `key`, if one is already queued or can be read from the
+    /// driver.
+    ///
+    /// If `blocking` is true this waits for a packet to arrive when none is already queued, the
+    /// same as the underlying driver's own blocking read; if false it polls the driver until either
+    /// a packet for `key` turns up or the driver has nothing left to read right now, rather than
+    /// waiting. Packets read along the way that belong to a different registered connection or
+    /// listener are queued there instead of being returned here.
+    fn poll_for(&mut self, key: ConnectionKey, blocking: bool) -> Option<Packet> {
+        loop {
+            if let Some(packet) = self.inbound.get_mut(&key).and_then(VecDeque::pop_front) {
+                return Some(packet);
+            }
+            if !self.route_incoming(blocking) {
+                return None;
+            }
+        }
+    }
+
+    /// Like `poll_for`, but for a `SocketListener` waiting on `local_port` rather than for an
+    /// established `ConnectionKey`.
+    fn poll_listener(&mut self, local_port: u32, blocking: bool) -> Option<Packet> {
+        loop {
+            if let Some(packet) = self.listening.get_mut(&local_port).and_then(VecDeque::pop_front) {
+                return Some(packet);
+            }
+            if !self.route_incoming(blocking) {
+                return None;
+            }
+        }
+    }
+}
+
 /// Connector to initiate a connection to a listener on the host.
 pub struct SocketConnector {
     /// The socket configuration.
     config: SocketConfiguration,
+    /// Whether the handshake completed and ownership of `config`'s registration was handed off to
+    /// the resulting `Socket`. If the connector is dropped before that happens, its `Drop` impl
+    /// unregisters the provisional key itself.
+    connected: bool,
 }
 
 impl SocketConnector {
-    pub fn new(vsock: VSock, host_port: u32, local_port: u32) -> Self {
+    pub fn new(multiplexer: MultiplexerHandle, host_port: u32, local_port: u32) -> Self {
+        let config = SocketConfiguration::new(multiplexer, local_port, host_port);
+        // Register our provisional key up front, the same as `Socket::new` does once connected, so
+        // the multiplexer can queue the handshake `Response` for us instead of dropping it or
+        // (worse) handing it to an unrelated caller that happens to be polling.
+        config.multiplexer.borrow_mut().register(config.key());
         Self {
-            config: SocketConfiguration::new(vsock, local_port, host_port),
+            config,
+            connected: false,
         }
     }
 
-    /// Tries to connect to a listener on the host.
+    /// Tries to connect to a listener on the host, waiting indefinitely for a response.
     ///
-    /// Since we don't yet support timeouts it will wait indefinitely for a respone. If the
-    /// connection is refused, or it receives an unexpected packet, it will return an error.
-    pub fn connect(mut self) -> anyhow::Result<Socket> {
+    /// If the connection is refused, or it receives an unexpected packet, it will return an error.
+    pub fn connect(self) -> anyhow::Result<Socket> {
+        self.connect_with_retries(None)
+    }
+
+    /// Like `connect`, but gives up with an error instead of waiting forever if `max_attempts`
+    /// non-blocking polls in a row come back without a response.
+    ///
+    /// We have no clock to measure wall-clock time against, so "how long to wait" is expressed as a
+    /// number of poll iterations rather than a duration.
+    pub fn connect_timeout(self, max_attempts: u32) -> anyhow::Result<Socket> {
+        self.connect_with_retries(Some(max_attempts))
+    }
+
+    fn connect_with_retries(mut self, max_attempts: Option<u32>) -> anyhow::Result<Socket> {
         let mut packet = Packet::new_control(
             self.config.local_port,
             self.config.host_port,
             VSockOp::Request,
         )?;
         // Set credit info.
-        packet.set_buf_alloc(STREAM_BUFFER_LENGTH.0);
+        packet.set_buf_alloc(RX_BUF_SIZE.0);
         packet.set_fwd_cnt(0);
-        self.config.vsock.write_packet(&mut packet);
-        let src_port = self.config.host_port;
-        let dst_port = self.config.local_port;
+        self.config.multiplexer.borrow_mut().write_packet(&mut packet);
+        let key = self.config.key();
+        let blocking = max_attempts.is_none();
+        let mut attempts = 0;
         loop {
-            if let Some(packet) = self.config.vsock.read_filtered_packet(
-                |packet| packet.get_dst_port() == dst_port && packet.get_src_port() == src_port,
-                true,
-            ) {
-                if packet.get_op()? == VSockOp::Response {
-                    break;
-                } else {
-                    anyhow::bail!(
-                        "Invalid response to connection request: {}",
-                        packet.get_op()?
-                    );
+            // Count this attempt before polling, so `max_attempts` of zero gives up without polling.
+            // `poll_for` routes packets addressed to other connections itself and only returns one
+            // addressed to us, so such unrelated traffic does not use up an attempt.
+            if let Some(max_attempts) = max_attempts {
+                if attempts >= max_attempts {
+                    anyhow::bail!("Timed out waiting for a connection response.");
                 }
+                attempts += 1;
             }
+            // Extract the packet (or `continue`) before doing anything else with `self.config`: the
+            // `borrow_mut()` temporary is kept alive for the whole `match` if we act on the packet
+            // inside its arm, and `Socket::new` below takes its own `borrow_mut()` on the same
+            // `RefCell` (the `.clone()` only clones the `Rc` pointer, not the cell) — a reentrant
+            // borrow that panics.
+            let packet = match self.config.multiplexer.borrow_mut().poll_for(key, blocking) {
+                Some(packet) => packet,
+                None => {
+                    // Either nothing is available yet (non-blocking case) or this was a spurious
+                    // empty poll while blocking; either way, just try again.
+                    continue;
+                }
+            };
+            if packet.get_op()? == VSockOp::Response {
+                // `Socket::new` re-registers the same key; this is a no-op since it is already
+                // registered, and hands ownership of the registration to the `Socket` from here on
+                // instead of `Drop` unregistering it.
+                self.connected = true;
+                return Ok(Socket::new(SocketConfiguration::new(
+                    self.config.multiplexer.clone(),
+                    self.config.local_port,
+                    self.config.host_port,
+                )));
+            } else {
+                anyhow::bail!("Invalid response to connection request: {}", packet.get_op()?);
+            }
+        }
+    }
+}
+
+impl Drop for SocketConnector {
+    fn drop(&mut self) {
+        if !self.connected {
+            self.config
+                .multiplexer
+                .borrow_mut()
+                .unregister(self.config.key());
         }
-        Ok(Socket::new(self.config))
     }
 }
 
-/// Listener that waits for a connection initiated from the host.
+/// Listener that waits for connections initiated from the host on a fixed local port.
+///
+/// Unlike `SocketConnector`, a listener is not tied to a single connection: it only borrows the
+/// multiplexer handle, so `accept` can be called repeatedly to serve one client after another on
+/// the same port.
 pub struct SocketListener {
-    /// The socket configuration.
-    config: SocketConfiguration,
+    /// The handle to the multiplexer that owns the underlying vsock device driver.
+    multiplexer: MultiplexerHandle,
+    /// The local port this listener is bound to.
+    local_port: u32,
 }
 
 impl SocketListener {
-    pub fn new(vsock: VSock, port: u32) -> Self {
+    pub fn new(multiplexer: MultiplexerHandle, port: u32) -> Self {
+        multiplexer.borrow_mut().register_listener(port);
         Self {
-            config: SocketConfiguration::new(vsock, port, 0),
+            multiplexer,
+            local_port: port,
         }
     }
 
-    /// Listens for a connection from the host on the specified port.
+    /// Waits indefinitely for the next connection from the host on this listener's port.
     ///
-    /// Since we don't yet support timeouts it will wait indefinitely for a connection request. If
-    /// it receives an unexpected packet (anything other than a connection request) it will return
-    /// an error.
-    pub fn accept(mut self) -> anyhow::Result<Socket> {
-        let dst_port = self.config.local_port;
-        loop {
-            if let Some(packet) = self
-                .config
-                .vsock
-                .read_filtered_packet(|packet| packet.get_dst_port() == dst_port, true)
-            {
-                if packet.get_op()? == VSockOp::Request {
-                    self.config.host_port = packet.get_src_port();
-                    break;
-                } else {
-                    anyhow::bail!("Invalid connection request: {}", packet.get_op()?);
+    /// If it receives an unexpected packet (anything other than a connection request) it will
+    /// return an error. The listener itself is left usable afterwards, so the caller can `accept`
+    /// again to serve the next client.
+    pub fn accept(&mut self) -> anyhow::Result<Socket> {
+        self.accept_with_retries(None)
+    }
+
+    /// Like `accept`, but gives up with an error instead of waiting forever if `max_attempts`
+    /// non-blocking polls in a row come back without a connection request.
+    ///
+    /// We have no clock to measure wall-clock time against, so "how long to wait" is expressed as a
+    /// number of poll iterations rather than a duration.
+    pub fn accept_timeout(&mut self, max_attempts: u32) -> anyhow::Result<Socket> {
+        self.accept_with_retries(Some(max_attempts))
+    }
+
+    fn accept_with_retries(&mut self, max_attempts: Option<u32>) -> anyhow::Result<Socket> {
+        let dst_port = self.local_port;
+        let blocking = max_attempts.is_none();
+        let mut attempts = 0;
+        let host_port = loop {
+            // Count this attempt before polling, so `max_attempts` of zero gives up without polling.
+            // `poll_listener` routes packets addressed to registered connections itself and only
+            // returns one addressed to this port, so such unrelated traffic does not use up an
+            // attempt.
+            if let Some(max_attempts) = max_attempts {
+                if attempts >= max_attempts {
+                    anyhow::bail!("Timed out waiting for a connection request.");
                 }
+                attempts += 1;
             }
-        }
+            // We don't know the host's port yet, so poll for a `Request` addressed to our local
+            // port from any host port, rather than a specific `ConnectionKey`.
+            match self.multiplexer.borrow_mut().poll_listener(dst_port, blocking) {
+                Some(packet) => {
+                    if packet.get_op()? == VSockOp::Request {
+                        break packet.get_src_port();
+                    } else {
+                        anyhow::bail!("Invalid connection request: {}", packet.get_op()?);
+                    }
+                }
+                None => {
+                    // Either nothing is available yet (non-blocking case) or this was a spurious
+                    // empty poll while blocking; either way, just try again.
+                }
+            }
+        };
 
-        let mut packet = Packet::new_control(
-            self.config.local_port,
-            self.config.host_port,
-            VSockOp::Response,
-        )?;
+        let mut packet = Packet::new_control(self.local_port, host_port, VSockOp::Response)?;
         // Set credit info.
-        packet.set_buf_alloc(STREAM_BUFFER_LENGTH.0);
+        packet.set_buf_alloc(RX_BUF_SIZE.0);
         packet.set_fwd_cnt(0);
-        self.config.vsock.write_packet(&mut packet);
+        self.multiplexer.borrow_mut().write_packet(&mut packet);
+
+        Ok(Socket::new(SocketConfiguration::new(
+            self.multiplexer.clone(),
+            self.local_port,
+            host_port,
+        )))
+    }
+}
 
-        Ok(Socket::new(self.config))
+impl Drop for SocketListener {
+    fn drop(&mut self) {
+        self.multiplexer.borrow_mut().unregister_listener(self.local_port);
     }
 }
 
@@ -160,10 +418,22 @@ pub struct Socket {
     peer_buffer_size: Wrapping<u32>,
     /// A temporary buffer to store extra data from a packet that was not fully read.
     pending_data: Option<VecDeque<u8>>,
+    /// Outbound data that has been handed to `write_all` but not yet sent, because it didn't fit in
+    /// a single packet or the peer didn't have enough credit for it yet.
+    tx_buffer: VecDeque<u8>,
+    /// Which of our own directions we have locally shut down via `shutdown()`.
+    local_shutdown: VSockFlags,
+    /// Which of the peer's directions it has told us (via an incoming `Shutdown` packet) are shut
+    /// down.
+    peer_shutdown: VSockFlags,
 }
 
 impl Socket {
     fn new(config: SocketConfiguration) -> Self {
+        config
+            .multiplexer
+            .borrow_mut()
+            .register(config.key());
         Self {
             config,
             connection_state: ConnectionState::Connected,
@@ -173,58 +443,95 @@ impl Socket {
             peer_processed_bytes: Wrapping(0),
             peer_buffer_size: Wrapping(0),
             pending_data: None,
+            tx_buffer: VecDeque::new(),
+            local_shutdown: VSockFlags::empty(),
+            peer_shutdown: VSockFlags::empty(),
         }
     }
 
-    /// Shuts the connection down.
+    /// Shuts down one or both directions of the connection locally.
     ///
-    /// At the moment this will cause the vsock driver to be dropped, which means that no future
-    /// connections will be possible. This should only be used if no further communications with the
-    /// host is expected.
-    pub fn shutdown(mut self) {
-        if self.connection_state == ConnectionState::Connected {
-            let mut packet = Packet::new_control(
-                self.config.local_port,
-                self.config.host_port,
-                VSockOp::Shutdown,
-            )
-            .expect("Could not create control packet.");
-            // Notify the host that we will not send or receive any more data packets.
-            packet.set_flags(VSockFlags::all());
-            self.config.vsock.write_packet(&mut packet);
+    /// This notifies the peer that we will not use the given direction(s) any more. Unlike a full
+    /// teardown, shutting down only one direction leaves the other usable: shutting down `Write`
+    /// still allows subsequent reads, and shutting down `Read` still allows subsequent writes. The
+    /// connection is only fully torn down (and an `Rst` sent) once both we and the peer have shut
+    /// down both directions; until then this can be called again to close the remaining direction.
+    pub fn shutdown(&mut self, how: Shutdown) -> anyhow::Result<()> {
+        if self.connection_state == ConnectionState::Disconnected {
+            return Ok(());
         }
+        let flags = how.into_flags();
+        if self.local_shutdown.contains(flags) {
+            // We already told the peer about this direction (or more).
+            return Ok(());
+        }
+        self.local_shutdown.insert(flags);
+        let mut packet = Packet::new_control(
+            self.config.local_port,
+            self.config.host_port,
+            VSockOp::Shutdown,
+        )?;
+        packet.set_flags(flags);
+        self.config
+            .multiplexer
+            .borrow_mut()
+            .write_packet(&mut packet);
+        self.update_close_state()
+    }
+
+    /// Recomputes `connection_state` from `local_shutdown`/`peer_shutdown`, sending the final `Rst`
+    /// and moving to `Disconnected` once both directions are closed on both ends.
+    fn update_close_state(&mut self) -> anyhow::Result<()> {
+        if self.local_shutdown.is_all() && self.peer_shutdown.is_all() {
+            self.send_control_packet(VSockOp::Rst)?;
+            self.connection_state = ConnectionState::Disconnected;
+        } else if !self.peer_shutdown.is_empty() {
+            self.connection_state = ConnectionState::PeerClosed {
+                no_more_recv: self.peer_shutdown.contains(VSockFlags::NO_MORE_RECEIVE),
+                no_more_send: self.peer_shutdown.contains(VSockFlags::NO_MORE_SEND),
+            };
+        } else if !self.local_shutdown.is_empty() {
+            self.connection_state = ConnectionState::LocalClosed;
+        }
+        Ok(())
     }
 
     /// Whether we should send an unsolicited credit update.
     fn must_send_credit_update(&self) -> bool {
-        STREAM_BUFFER_LENGTH - (self.processed_bytes - self.previous_processed_bytes)
-            < CREDIT_UPDATE_LIMIT
+        let peer_seen_free_buf = RX_BUF_SIZE - (self.processed_bytes - self.previous_processed_bytes);
+        peer_seen_free_buf < CREDIT_UPDATE_THRESHOLD
     }
 
     /// Sends a control packet with the specified op to the host.
     fn send_control_packet(&mut self, op: VSockOp) -> anyhow::Result<()> {
-        // For now we panic if we are disconnected.
-        assert!(self.connection_state == ConnectionState::Connected);
+        if self.connection_state == ConnectionState::Disconnected {
+            anyhow::bail!("Cannot send: stream disconnected.");
+        }
         let mut packet = Packet::new_control(self.config.local_port, self.config.host_port, op)?;
         self.set_credit_info(&mut packet);
-        self.config.vsock.write_packet(&mut packet);
+        self.config
+            .multiplexer
+            .borrow_mut()
+            .write_packet(&mut packet);
         Ok(())
     }
 
     /// Sends a data packet to the host.
     fn send_data_packet(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        // For now we panic if we are disconnected.
-        assert!(
-            self.connection_state == ConnectionState::Connected,
-            "Stream disconnected."
-        );
+        if self.connection_state == ConnectionState::Disconnected {
+            anyhow::bail!("Cannot write: stream disconnected.");
+        }
+        if self.local_shutdown.contains(VSockFlags::NO_MORE_SEND) {
+            anyhow::bail!("Cannot write: the send side of this connection was shut down.");
+        }
         let data_len = data.len();
-        assert!(
-            data_len <= MAX_PAYLOAD_SIZE,
-            "The data is too large for a single packet. Len: {}, Max: {}",
-            data.len(),
-            MAX_PAYLOAD_SIZE
-        );
+        if data_len > MAX_PAYLOAD_SIZE {
+            anyhow::bail!(
+                "The data is too large for a single packet. Len: {}, Max: {}",
+                data_len,
+                MAX_PAYLOAD_SIZE
+            );
+        }
 
         let data_len = Wrapping(data_len as u32);
         if data_len > self.peer_buffer_size - (self.sent_bytes - self.peer_processed_bytes) {
@@ -234,75 +541,182 @@ impl Socket {
         self.sent_bytes += data_len;
         let mut packet = Packet::new_data(data, self.config.local_port, self.config.host_port)?;
         self.set_credit_info(&mut packet);
-        self.config.vsock.write_packet(&mut packet);
+        self.config
+            .multiplexer
+            .borrow_mut()
+            .write_packet(&mut packet);
         Ok(())
     }
 
+    /// Sends as many packets out of `tx_buffer` as the peer's advertised credit currently allows,
+    /// leaving whatever doesn't fit for a later call.
+    fn send_buffered(&mut self) -> anyhow::Result<()> {
+        loop {
+            if self.tx_buffer.is_empty() {
+                return Ok(());
+            }
+            let peer_free = (self.peer_buffer_size - (self.sent_bytes - self.peer_processed_bytes)).0
+                as usize;
+            if peer_free == 0 {
+                return Ok(());
+            }
+            let chunk_len = MAX_PAYLOAD_SIZE.min(self.tx_buffer.len()).min(peer_free);
+            let chunk: Vec<u8> = self.tx_buffer.drain(..chunk_len).collect();
+            self.send_data_packet(&chunk)?;
+        }
+    }
+
     /// Updates the credit info on a packet to facilitate flow-control.
     fn set_credit_info(&mut self, packet: &mut Packet) {
-        packet.set_buf_alloc(STREAM_BUFFER_LENGTH.0);
+        packet.set_buf_alloc(RX_BUF_SIZE.0);
         packet.set_fwd_cnt(self.processed_bytes.0);
         self.previous_processed_bytes = self.processed_bytes;
     }
 
-    /// Reads the payload of the next available data packet, if any are available.
-    fn read_data(&mut self) -> Option<VecDeque<u8>> {
-        // For now we panic if we are disconnected.
-        assert!(
-            self.connection_state == ConnectionState::Connected,
-            "Stream disconnected."
-        );
-        let src_port = self.config.host_port;
-        let dst_port = self.config.local_port;
-        loop {
-            let packet = self.config.vsock.read_filtered_packet(
-                |packet| packet.get_dst_port() == dst_port && packet.get_src_port() == src_port,
-                true,
-            )?;
-            self.peer_buffer_size = Wrapping(packet.get_buf_alloc());
-            self.peer_processed_bytes = Wrapping(packet.get_fwd_cnt());
-            // For now we panic if we receive an invalid op.
-            match packet.get_op().expect("Invalid packet received on stream.") {
-                VSockOp::CreditRequest => {
-                    self.send_control_packet(VSockOp::CreditUpdate)
-                        .expect("Could not create control packet.");
-                }
-                VSockOp::CreditUpdate => {
-                    // We already updated our flow-control tracking data, so do nothing.
-                }
-                VSockOp::Request | VSockOp::Response => {
-                    // For now we panic if we receive an invalid op.
-                    panic!("Invalid packet received on stream.");
-                }
-                VSockOp::Rst => {
-                    self.connection_state = ConnectionState::Disconnected;
-                    return None;
-                }
-                VSockOp::Shutdown => {
-                    self.send_control_packet(VSockOp::Rst)
-                        .expect("Could not create control packet.");
-                    self.connection_state = ConnectionState::Disconnected;
-                    return None;
-                }
-                VSockOp::Rw => {
-                    let data = packet.get_payload();
-                    // TODO(#2876): Avoid copying the buffer slice if possible.
-                    let mut result = VecDeque::with_capacity(data.len());
-                    result.extend(data);
-                    return Some(result);
+    /// Aborts the connection in response to a protocol violation from the peer: sends an `Rst`
+    /// (best-effort, since the peer is the one misbehaving), transitions to `Disconnected`, and
+    /// returns an error describing what went wrong instead of panicking. A guest should never crash
+    /// because an untrusted host sent a malformed or out-of-state packet.
+    fn abort_on_protocol_violation(&mut self, description: impl core::fmt::Display) -> anyhow::Error {
+        // Send the `Rst` before marking ourselves disconnected: `send_control_packet` refuses to
+        // send anything once we are in that state.
+        let _ = self.send_control_packet(VSockOp::Rst);
+        self.connection_state = ConnectionState::Disconnected;
+        anyhow::anyhow!("Protocol violation, resetting connection: {}", description)
+    }
+
+    /// Polls for and processes a single packet for this connection, optionally blocking until one
+    /// is available.
+    ///
+    /// This returns after processing exactly one packet, even if that packet carried no payload
+    /// (e.g. a `CreditRequest`/`CreditUpdate`, or a `Shutdown` that only closed one direction): the
+    /// caller decides whether such a `ReadStep::Progress` is enough to act on (as `flush` does, to
+    /// re-check whether credit has freed up) or should simply be retried (as `read_data` does, since
+    /// it only cares about `Data`/`Eof`).
+    ///
+    /// A blocking poll that comes back empty is a spurious wake-up (e.g. an interrupt fired but
+    /// there was nothing to read by the time we looked), not a disconnect, so it is simply retried;
+    /// `ReadStep::WouldBlock` is therefore only ever returned when `blocking` is false.
+    fn read_step(&mut self, blocking: bool) -> anyhow::Result<ReadStep> {
+        if self.peer_shutdown.contains(VSockFlags::NO_MORE_SEND) {
+            return Ok(ReadStep::Eof);
+        }
+        let key = self.config.key();
+        let packet = loop {
+            match self.config.multiplexer.borrow_mut().poll_for(key, blocking) {
+                Some(packet) => break packet,
+                None if blocking => continue,
+                None => return Ok(ReadStep::WouldBlock),
+            }
+        };
+        self.peer_buffer_size = Wrapping(packet.get_buf_alloc());
+        self.peer_processed_bytes = Wrapping(packet.get_fwd_cnt());
+        let op = match packet.get_op() {
+            Ok(op) => op,
+            Err(error) => return Err(self.abort_on_protocol_violation(error)),
+        };
+        Ok(match op {
+            VSockOp::CreditRequest => {
+                self.send_control_packet(VSockOp::CreditUpdate)?;
+                ReadStep::Progress
+            }
+            VSockOp::CreditUpdate => {
+                // We already updated our flow-control tracking data above, so there's nothing more
+                // to do; returning here (rather than looping for the next packet) is what lets
+                // `flush` notice the peer's credit grew and re-check `send_buffered` right away.
+                ReadStep::Progress
+            }
+            VSockOp::Request | VSockOp::Response => {
+                return Err(self.abort_on_protocol_violation(op));
+            }
+            VSockOp::Rst => {
+                self.connection_state = ConnectionState::Disconnected;
+                ReadStep::Eof
+            }
+            VSockOp::Shutdown => {
+                let flags = packet.get_flags();
+                self.peer_shutdown.insert(flags);
+                self.update_close_state()?;
+                if self.connection_state == ConnectionState::Disconnected
+                    || flags.contains(VSockFlags::NO_MORE_SEND)
+                {
+                    // Either both sides are fully closed, or the peer specifically told us it will
+                    // not send any more data: there is nothing left to wait for.
+                    ReadStep::Eof
+                } else {
+                    // The peer only shut down its receive side; it may still send us data.
+                    ReadStep::Progress
                 }
             }
+            VSockOp::Rw => {
+                let data = packet.get_payload();
+                // TODO(#2876): Avoid copying the buffer slice if possible.
+                let mut result = VecDeque::with_capacity(data.len());
+                result.extend(data);
+                ReadStep::Data(result)
+            }
+        })
+    }
+
+    /// Reads the payload of the next available data packet, blocking until one is available.
+    ///
+    /// Returns `None` both when the connection is torn down and when the peer has shut down its
+    /// send side: either way, no further payload will ever arrive. Any data already buffered in
+    /// `pending_data` is drained by the caller (`read_partial`) before this is reached, so a peer
+    /// shutdown never discards payload that was already received.
+    fn read_data(&mut self) -> anyhow::Result<Option<VecDeque<u8>>> {
+        if self.connection_state == ConnectionState::Disconnected {
+            anyhow::bail!("Stream disconnected.");
+        }
+        loop {
+            match self.read_step(true)? {
+                ReadStep::Data(data) => return Ok(Some(data)),
+                ReadStep::Eof => return Ok(None),
+                // Not a data packet; keep waiting for one.
+                ReadStep::Progress => continue,
+                ReadStep::WouldBlock => unreachable!("a blocking read_step never returns WouldBlock"),
+            }
+        }
+    }
+
+    /// Makes a single non-blocking attempt to read the next available data packet.
+    ///
+    /// Returns `PollOutcome::WouldBlock` if nothing was available right now rather than waiting for
+    /// it, which lets a caller drive several sockets cooperatively (polling each of them in turn)
+    /// instead of dedicating a thread to each one.
+    /// `Ready(None)` means no further payload will ever arrive (the connection ended or the peer
+    /// shut down its send side), matching the distinction `read_data` draws with its `Option`
+    /// result -- a caller driving this non-blocking API needs to be able to tell that apart from
+    /// `Ready(Some(data))` with a genuinely empty `data`.
+    pub fn try_read_data(&mut self) -> anyhow::Result<PollOutcome<Option<VecDeque<u8>>>> {
+        if self.connection_state == ConnectionState::Disconnected {
+            anyhow::bail!("Stream disconnected.");
+        }
+        loop {
+            match self.read_step(false)? {
+                ReadStep::Data(data) => return Ok(PollOutcome::Ready(Some(data))),
+                ReadStep::Eof => return Ok(PollOutcome::Ready(None)),
+                // Not a data packet; another one may already be queued, so keep trying rather than
+                // reporting `WouldBlock` when there might be more to read right now.
+                ReadStep::Progress => continue,
+                ReadStep::WouldBlock => return Ok(PollOutcome::WouldBlock),
+            }
         }
     }
 
     /// Tries once to fill the destination with as much data as is currently available, either in
     /// the pending buffer or from the next available data packet.
     ///
-    /// Returns the number of bytes read if any data was available to read.
-    fn read_partial(&mut self, dest: &mut [u8]) -> Option<usize> {
+    /// Returns the number of bytes read, or an error if the connection was already disconnected or
+    /// the peer violated the protocol. Returns `Ok(0)` once no further payload will arrive, whether
+    /// the peer shut down its send side or reset the connection while we were waiting.
+    fn read_partial(&mut self, dest: &mut [u8]) -> anyhow::Result<usize> {
         let mut source = match self.pending_data.take() {
             Some(data) => data,
-            None => self.read_data()?,
+            None => match self.read_data()? {
+                Some(data) => data,
+                None => return Ok(0),
+            },
         };
 
         let len = dest.len();
@@ -315,7 +729,17 @@ impl Socket {
         if !source.is_empty() {
             self.pending_data.replace(source);
         }
-        Some(position)
+        Ok(position)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if self.connection_state != ConnectionState::Disconnected {
+            // Best-effort: let the peer know we're going away if we haven't already said so.
+            let _ = self.shutdown(Shutdown::Both);
+        }
+        self.config.multiplexer.borrow_mut().unregister(self.config.key());
     }
 }
 
@@ -326,7 +750,12 @@ impl ciborium_io::Read for Socket {
         let len = data.len();
         let mut count = 0;
         while count < len {
-            count += self.read_partial(&mut data[count..]).unwrap_or(0);
+            let read = self.read_partial(&mut data[count..])?;
+            if read == 0 {
+                self.processed_bytes += Wrapping(count as u32);
+                anyhow::bail!("Connection closed before all requested data was read.");
+            }
+            count += read;
         }
 
         self.processed_bytes += Wrapping(count as u32);
@@ -343,38 +772,140 @@ impl ciborium_io::Read for Socket {
 impl ciborium_io::Write for Socket {
     type Error = anyhow::Error;
 
+    /// Appends `data` to the outbound TX buffer, flushing whenever the buffer fills up, and then
+    /// opportunistically sends as much of what is buffered as the peer currently has credit for.
     fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error> {
-        let mut start = 0;
-        let data_len = data.len();
-        while start < data_len {
-            let end = core::cmp::min(data_len, start + MAX_PAYLOAD_SIZE);
-            self.send_data_packet(&data[start..end])?;
-            start = end;
+        let mut offset = 0;
+        while offset < data.len() {
+            if self.tx_buffer.len() >= TX_BUFFER_CAPACITY {
+                self.flush()?;
+            }
+            let space = TX_BUFFER_CAPACITY - self.tx_buffer.len();
+            let end = offset + core::cmp::min(space, data.len() - offset);
+            self.tx_buffer.extend(&data[offset..end]);
+            offset = end;
         }
-        Ok(())
+        self.send_buffered()
+            .map_err(|error| anyhow::anyhow!("Could not write data: {:?}", error))
     }
 
+    /// Blocks until the entire outbound TX buffer has been sent to the peer.
+    ///
+    /// If the peer's advertised buffer space runs out while we still have data queued, this sends a
+    /// `VSockOp::CreditRequest` and waits for a single step of progress (by calling `read_step`
+    /// directly rather than `read_data`, since the expected reply is a bare `VSockOp::CreditUpdate`
+    /// with no payload, which `read_data` would otherwise keep waiting past) before re-checking
+    /// whether there's now room to send.
     fn flush(&mut self) -> Result<(), Self::Error> {
-        // We always flush on write, so do nothing.
-        // TODO(#2876): We should use a bufferd writer so that we don't always flush on write, and
-        // provide and actual flush implementation here.
+        self.send_buffered()
+            .map_err(|error| anyhow::anyhow!("Could not flush: {:?}", error))?;
+        while !self.tx_buffer.is_empty() {
+            if self.connection_state == ConnectionState::Disconnected {
+                anyhow::bail!("Could not flush: stream disconnected.");
+            }
+            self.send_control_packet(VSockOp::CreditRequest)
+                .map_err(|error| anyhow::anyhow!("Could not request credit: {:?}", error))?;
+            match self
+                .read_step(true)
+                .map_err(|error| anyhow::anyhow!("Could not flush: {:?}", error))?
+            {
+                // This also absorbs any data the peer happens to send us while we wait; stash it in
+                // `pending_data` instead of dropping it so a subsequent read can still observe it.
+                ReadStep::Data(data) => match &mut self.pending_data {
+                    Some(pending) => pending.extend(data),
+                    None => self.pending_data = Some(data),
+                },
+                ReadStep::Eof => anyhow::bail!("Could not flush: stream disconnected."),
+                // Likely the `CreditUpdate` reply we asked for; `read_step` already updated our view
+                // of the peer's credit, so loop around and try sending again.
+                ReadStep::Progress => {}
+                ReadStep::WouldBlock => unreachable!("a blocking read_step never returns WouldBlock"),
+            }
+            self.send_buffered()
+                .map_err(|error| anyhow::anyhow!("Could not flush: {:?}", error))?;
+        }
         Ok(())
     }
 }
 
+/// The outcome of a non-blocking attempt to make progress on a connection.
+///
+/// We have no clock available, so retrying is expressed in poll iterations rather than a timeout
+/// duration: callers that want to give up after a while (e.g. `SocketConnector::connect_timeout`)
+/// count attempts themselves instead of measuring elapsed time.
+pub enum PollOutcome<T> {
+    /// The operation completed.
+    Ready(T),
+    /// Nothing was available yet; try again later.
+    WouldBlock,
+}
+
+/// The result of one step of processing inbound packets for a connection, used internally to share
+/// logic between the blocking `read_data` and the non-blocking `try_read_data`.
+enum ReadStep {
+    /// A data packet's payload.
+    Data(VecDeque<u8>),
+    /// A packet was processed but carried no payload (e.g. a credit exchange, or a `Shutdown` that
+    /// only closed one direction). Callers that only care about payload (`read_data`) loop past this;
+    /// `flush` uses it as a signal to re-check whether the peer's credit has freed up.
+    Progress,
+    /// Nothing was available; only returned for a non-blocking step.
+    WouldBlock,
+    /// No further payload will ever arrive, because the connection ended or the peer shut down its
+    /// send side.
+    Eof,
+}
+
+/// Which direction(s) of a connection to shut down with [`Socket::shutdown`].
+pub enum Shutdown {
+    /// Stop sending data to the peer. Reads are still possible until the peer also shuts down its
+    /// send side.
+    Write,
+    /// Stop accepting data from the peer. Writes are still possible until the peer also shuts down
+    /// its receive side.
+    Read,
+    /// Stop both sending and receiving.
+    Both,
+}
+
+impl Shutdown {
+    fn into_flags(self) -> VSockFlags {
+        match self {
+            Shutdown::Write => VSockFlags::NO_MORE_SEND,
+            Shutdown::Read => VSockFlags::NO_MORE_RECEIVE,
+            Shutdown::Both => VSockFlags::all(),
+        }
+    }
+}
+
 /// The state of the connection.
-#[derive(PartialEq, Eq)]
+///
+/// Closing a vsock connection is a half-close handshake, modeled on the Firecracker/cloud-hypervisor
+/// vsock connection state machine: either side can shut down its send and/or receive direction
+/// independently, and the connection is only fully torn down once both directions are closed on
+/// both ends.
+#[derive(PartialEq, Eq, Clone, Copy)]
 enum ConnectionState {
+    /// Both directions are open.
     Connected,
+    /// The peer has shut down one or both directions; see the fields for which. We may still be
+    /// locally closed in some direction too -- see `Socket::local_shutdown` for the full picture.
+    PeerClosed {
+        no_more_recv: bool,
+        no_more_send: bool,
+    },
+    /// We have locally shut down at least one direction via `shutdown()`; see
+    /// `Socket::local_shutdown` for which.
+    LocalClosed,
+    /// Both directions are closed, by us, by the peer, or by a protocol violation; the connection
+    /// is no longer usable.
     Disconnected,
 }
 
 /// The configuration information for the socket.
 struct SocketConfiguration {
-    /// The vsock device driver.
-    ///
-    /// For now we only support one connection, so the driver is owned by this configuration.
-    vsock: VSock,
+    /// The handle to the multiplexer that owns the underlying vsock device driver.
+    multiplexer: MultiplexerHandle,
     /// The local port for the connection.
     local_port: u32,
     /// The host port for the connection.
@@ -382,11 +913,16 @@ struct SocketConfiguration {
 }
 
 impl SocketConfiguration {
-    fn new(vsock: VSock, local_port: u32, host_port: u32) -> Self {
+    fn new(multiplexer: MultiplexerHandle, local_port: u32, host_port: u32) -> Self {
         Self {
-            vsock,
+            multiplexer,
             local_port,
             host_port,
         }
     }
+
+    /// The key this connection is (or will be) registered under in the multiplexer.
+    fn key(&self) -> ConnectionKey {
+        (self.local_port, self.host_port)
+    }
 }